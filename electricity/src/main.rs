@@ -1,13 +1,27 @@
-use std::{net::ToSocketAddrs, sync::Arc};
-
-use futures::stream;
+use std::{
+    collections::HashMap,
+    net::ToSocketAddrs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{stream, SinkExt, StreamExt};
 use influxdb2_client::{models::DataPoint, Client as InfluxClient};
-use modbus::{Client, Config, Transport};
+use modbus::{Config, Transport};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use tokio::{io::AsyncReadExt, net::TcpStream, sync::Mutex};
+use sd_notify::NotifyState;
+use serde::Serialize;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, Mutex},
+};
+use tokio_tungstenite::tungstenite::Message;
 use tracing::{debug, error, info, trace, warn};
 
+mod register_map;
+
 static INFLUX_HOST: Lazy<String> =
     Lazy::new(|| std::env::var("INFLUX_HOST").unwrap_or_else(|_| "localhost:8086".to_string()));
 
@@ -16,6 +30,73 @@ static INFLUX_TOKEN: Lazy<String> = Lazy::new(|| std::env::var("INFLUX_TOKEN").u
 static INVERTER_HOST: Lazy<String> =
     Lazy::new(|| std::env::var("INVERTER_HOST").unwrap_or_else(|_| "localhost:1502".to_string()));
 
+static WS_ADDR: Lazy<String> =
+    Lazy::new(|| std::env::var("WS_HOST").unwrap_or_else(|_| "0.0.0.0:36083".to_string()));
+
+/// A single computed reading, broadcast to every subscribed WebSocket client
+/// as a JSON frame in addition to the InfluxDB write.
+#[derive(Debug, Clone, Serialize)]
+pub struct Reading {
+    pub import: i64,
+    pub export: i64,
+    pub production: i64,
+    pub usage: i64,
+    pub lifetime_usage: i64,
+    /// Register-map metrics other than the production and production-lifetime
+    /// role metrics (the first is `production` above, the second only feeds
+    /// `lifetime_usage`), keyed by each metric's configured name.
+    pub register_fields: HashMap<String, f64>,
+    /// Raw OBIS code -> value pairs, exactly as parsed from the P1 telegram. Kept
+    /// separate from `register_fields` so a metric name can never collide with
+    /// (and be silently overwritten by) an OBIS code, or vice versa.
+    pub obis_fields: HashMap<String, f64>,
+}
+
+/// Shared handle for fanning computed readings out to WebSocket subscribers.
+/// `latest` is kept alongside the broadcast channel so a client that connects
+/// between readings still gets something to render immediately.
+#[derive(Clone)]
+struct ReadingsFeed {
+    tx: broadcast::Sender<Reading>,
+    latest: Arc<Mutex<Option<Reading>>>,
+}
+
+impl ReadingsFeed {
+    fn new() -> Self {
+        let (tx, _) = broadcast::channel(16);
+        Self {
+            tx,
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn publish(&self, reading: Reading) {
+        *self.latest.lock().await = Some(reading.clone());
+        // No subscribers is the common case between dashboard connections; not an error.
+        let _ = self.tx.send(reading);
+    }
+}
+
+/// Tracks when the pipeline last made real progress (a modbus register read
+/// or an InfluxDB write succeeded), so the systemd watchdog ping can be tied
+/// to actual liveness rather than just "the process is still scheduled".
+#[derive(Clone)]
+struct PollHealth(Arc<std::sync::Mutex<Instant>>);
+
+impl PollHealth {
+    fn new() -> Self {
+        Self(Arc::new(std::sync::Mutex::new(Instant::now())))
+    }
+
+    fn mark_success(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn healthy_within(&self, interval: Duration) -> bool {
+        self.0.lock().unwrap().elapsed() < interval
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum EnergyRecordError {
     #[error("failed to parse energy record")]
@@ -24,6 +105,19 @@ pub enum EnergyRecordError {
     OtherError(),
 }
 
+/// Errors raised while polling the inverter over Modbus. These are always
+/// logged and swallowed by the caller rather than propagated, since a single
+/// bad register read should never tear down the whole connection.
+#[derive(Debug, thiserror::Error)]
+pub enum PollError {
+    #[error("modbus error: {0}")]
+    Modbus(#[from] modbus::Error),
+    #[error("malformed response from inverter: {0}")]
+    MalformedResponse(&'static str),
+    #[error("failed to resolve inverter address: {0}")]
+    Io(#[from] std::io::Error),
+}
+
 #[derive(Debug)]
 pub struct EnergyRecord {
     pub obis_code: String,
@@ -52,23 +146,31 @@ impl TryFrom<String> for EnergyRecord {
     }
 }
 
-fn get_modbus_pipe() -> Transport {
+/// Dial (or re-dial) the modbus transport. Fallible so a momentarily
+/// unreachable inverter can be logged and retried by the caller instead of
+/// panicking the connection task that triggered the reconnect.
+pub(crate) fn get_modbus_pipe() -> Result<Transport, PollError> {
     let addr = INVERTER_HOST
-        .to_socket_addrs()
-        .expect("invalid modbus client address")
+        .to_socket_addrs()?
         .next()
-        .expect("invalid modbus client address");
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                "INVERTER_HOST did not resolve to an address",
+            )
+        })?;
 
     info!(address = ?addr, "connecting to modbus client");
 
-    modbus::tcp::Transport::new_with_cfg(
+    let transport = modbus::tcp::Transport::new_with_cfg(
         addr.ip().to_string().as_str(),
         Config {
             tcp_port: addr.port(),
             ..Default::default()
         },
-    )
-    .unwrap()
+    )?;
+
+    Ok(transport)
 }
 
 #[tokio::main]
@@ -77,7 +179,7 @@ async fn main() {
     info!("starting server");
 
     // Connect to the modbus client
-    let client = get_modbus_pipe();
+    let client = get_modbus_pipe().expect("failed to connect to modbus client");
     let modbus_client = Arc::new(Mutex::new(client));
 
     // Connect to the influxdb client
@@ -85,31 +187,147 @@ async fn main() {
         influxdb2_client::Client::new(INFLUX_HOST.to_string(), INFLUX_TOKEN.as_str());
     let influx_client = Arc::new(influx_client);
 
+    // Shared fan-out of computed readings to WebSocket subscribers
+    let readings_feed = ReadingsFeed::new();
+
     // Bind to the TCP port
     let addr = "0.0.0.0:36082";
     let listener = tokio::net::TcpListener::bind(addr)
         .await
         .expect("failed to listen to tcp port");
 
+    // Bind the WebSocket port
+    let ws_listener = TcpListener::bind(WS_ADDR.as_str())
+        .await
+        .expect("failed to listen to websocket port");
+
+    // Both the modbus transport and the TCP listener are up, tell systemd we're ready
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        debug!(error = ?e, "failed to notify systemd of readiness (not running under systemd?)");
+    }
+
+    let poll_health = PollHealth::new();
+
+    // If a systemd watchdog timeout is configured, ping it at half the
+    // interval, but only while the pipeline is actually making progress
+    if let Some(interval) = sd_notify::watchdog_enabled(false) {
+        let poll_health = poll_health.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval / 2);
+            loop {
+                ticker.tick().await;
+
+                if poll_health.healthy_within(interval) {
+                    if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                        warn!(error = ?e, "failed to send watchdog keep-alive");
+                    }
+                } else {
+                    warn!("no successful modbus read or influxdb write within watchdog interval, withholding keep-alive");
+                }
+            }
+        });
+    }
+
+    // Accept WebSocket subscribers on their own task
+    tokio::spawn({
+        let readings_feed = readings_feed.clone();
+        async move {
+            loop {
+                let (socket, _) = match ws_listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!(error = ?e, "failed to accept websocket socket");
+                        continue;
+                    }
+                };
+                let readings_feed = readings_feed.clone();
+
+                tokio::spawn(async move {
+                    info!(address = ?socket.peer_addr(), "accepted new websocket connection");
+                    handle_ws_connection(socket, readings_feed).await;
+                });
+            }
+        }
+    });
+
     // Accept connections
     loop {
         let (socket, _) = listener.accept().await.expect("failed to accept socket");
         let influx_client = influx_client.clone();
         let modbus_client = modbus_client.clone();
+        let readings_feed = readings_feed.clone();
+        let poll_health = poll_health.clone();
 
         // Spawn a new task to handle the connection
         tokio::spawn(async move {
             info!(address = ?socket.peer_addr(), "accepted new connection");
-            handle_connection(socket, influx_client, modbus_client).await;
+            handle_connection(
+                socket,
+                influx_client,
+                modbus_client,
+                readings_feed,
+                poll_health,
+            )
+            .await;
         });
     }
 }
 
+/// Serve a single WebSocket subscriber: send it the most recent reading (if
+/// any) so it isn't blank before the next meter telegram arrives, then stream
+/// every subsequently published reading as a JSON text frame. A client that
+/// falls more than the channel's capacity behind skips the readings it missed.
+async fn handle_ws_connection(socket: TcpStream, readings_feed: ReadingsFeed) {
+    let mut ws_stream = match tokio_tungstenite::accept_async(socket).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            error!(error = ?e, "failed to complete websocket handshake");
+            return;
+        }
+    };
+
+    // Subscribe before taking the snapshot so a reading published in between isn't lost
+    let mut rx = readings_feed.tx.subscribe();
+    if let Some(reading) = readings_feed.latest.lock().await.clone() {
+        if let Err(e) = send_reading(&mut ws_stream, &reading).await {
+            warn!(error = ?e, "failed to send initial snapshot to websocket client");
+            return;
+        }
+    }
+
+    loop {
+        let reading = match rx.recv().await {
+            Ok(reading) => reading,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(skipped, "websocket client lagged behind readings feed");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if let Err(e) = send_reading(&mut ws_stream, &reading).await {
+            warn!(error = ?e, "failed to send reading to websocket client, dropping connection");
+            break;
+        }
+    }
+}
+
+async fn send_reading(
+    ws_stream: &mut tokio_tungstenite::WebSocketStream<TcpStream>,
+    reading: &Reading,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payload = serde_json::to_string(reading)?;
+    ws_stream.send(Message::Text(payload)).await?;
+    Ok(())
+}
+
 #[tracing::instrument(skip_all, fields(address = ?socket.peer_addr()))]
 async fn handle_connection(
     mut socket: TcpStream,
     influx_client: Arc<InfluxClient>,
     modbus_client: Arc<Mutex<Transport>>,
+    readings_feed: ReadingsFeed,
+    poll_health: PollHealth,
 ) {
     loop {
         // Packages are larger than the TCP buffer, so we need to read in chunks and combine them
@@ -138,15 +356,33 @@ async fn handle_connection(
 
             full_packet.push_str(&tokens);
 
-            // Get production value from solaredge, used to calculate consumption
+            // Read every metric configured in the register map
             // (yes I know, data is not completely accurate due to the transmit delay from the esp12f and modbus polling delay)
-            let production = get_ac_production(&modbus_client).await;
+            let register_values =
+                register_map::read_all(&modbus_client, &register_map::REGISTER_MAP).await;
+            if !register_values.is_empty() {
+                poll_health.mark_success();
+            }
+
+            // Production value from the inverter, used to calculate consumption
+            let production_metric = register_map::REGISTER_MAP
+                .get_by_role(register_map::MetricRole::Production)
+                .expect("register map was validated at load time to include a production metric");
+            let production = match register_values.get(&production_metric.name) {
+                Some(value) => *value as i64,
+                None => {
+                    error!(metric = %production_metric.name, "register map did not yield a production reading, skipping packet");
+                    continue;
+                }
+            };
 
             // Split the packet with regex
             let full = full_packet.clone().replace("\r\n", "");
             let regex = Regex::new(r"(?m)1-0:(.*?)\((.*?)\*(.*?)\)").unwrap();
 
             let mut data_point_builder = DataPoint::builder("energy");
+            let mut register_fields = HashMap::new();
+            let mut obis_fields = HashMap::new();
 
             // export and import for the current packet
             let mut export = 0;
@@ -156,29 +392,41 @@ async fn handle_connection(
             let mut imported_lifetime = 0;
             let mut exported_lifetime = 0;
 
-            // Get the production lifetime
-            let mut cl = modbus_client.lock().await;
-            let production_lifetime = cl.read_holding_registers(93, 2).unwrap();
-            let production_lifetime_scale = cl.read_holding_registers(95, 1).unwrap();
-
-            // Convert Vec<u16> to a single value
-            let production_lifetime = if production_lifetime.len() == 2 {
-                ((production_lifetime[0] as u32) << 16) | (production_lifetime[1] as u32)
-            } else {
-                panic!("Unexpected vector size");
+            // Lifetime production from the inverter, used to calculate lifetime consumption
+            let production_lifetime_metric = register_map::REGISTER_MAP
+                .get_by_role(register_map::MetricRole::ProductionLifetime)
+                .expect(
+                    "register map was validated at load time to include a production_lifetime metric",
+                );
+            let production_lifetime = match register_values.get(&production_lifetime_metric.name) {
+                Some(value) => *value as i64,
+                None => {
+                    error!(metric = %production_lifetime_metric.name, "register map did not yield a production_lifetime reading, skipping packet");
+                    continue;
+                }
             };
 
-            let production_lifetime_scale = *production_lifetime_scale.first().unwrap() as i16;
-            let production_lifetime = ((production_lifetime as f64)
-                * (10_f64.powf(production_lifetime_scale as f64)).floor())
-                as i64;
+            // Add any other configured register metrics straight to the data point
+            for (name, value) in &register_values {
+                if name == &production_metric.name || name == &production_lifetime_metric.name {
+                    continue;
+                }
+                data_point_builder = data_point_builder.field(name.as_str(), *value);
+                register_fields.insert(name.clone(), *value);
+            }
 
             // Parse the packet
             for capture in regex.captures_iter(&full) {
                 let (_, [obis_code, value, unit]) = capture.extract();
                 debug!(?obis_code, ?value, ?unit);
 
-                let value = value.parse::<f64>().unwrap();
+                let value = match value.parse::<f64>() {
+                    Ok(value) => value,
+                    Err(e) => {
+                        warn!(error = ?e, ?obis_code, ?value, "failed to parse field value, skipping field");
+                        continue;
+                    }
+                };
 
                 // Convert to W and extract values
                 match obis_code {
@@ -190,6 +438,7 @@ async fn handle_connection(
                 }
 
                 data_point_builder = data_point_builder.field(obis_code, value);
+                obis_fields.insert(obis_code.to_string(), value);
             }
 
             // Calculate consumption
@@ -210,6 +459,19 @@ async fn handle_connection(
             data_point_builder = data_point_builder.field("lifetime_usage", lifetime_usage);
             debug!(?lifetime_usage);
 
+            // Push the reading out to any subscribed websocket clients
+            readings_feed
+                .publish(Reading {
+                    import,
+                    export,
+                    production,
+                    usage,
+                    lifetime_usage,
+                    register_fields,
+                    obis_fields,
+                })
+                .await;
+
             // Build the data point
             let data_point = match data_point_builder.build() {
                 Ok(dp) => dp,
@@ -226,7 +488,10 @@ async fn handle_connection(
                 .write("home", "electricity", stream::iter(vec![data_point]))
                 .await
             {
-                Ok(_) => info!("successfully wrote to influxdb"),
+                Ok(_) => {
+                    poll_health.mark_success();
+                    info!("successfully wrote to influxdb");
+                }
                 Err(e) => error!(error = ?e, "failed to write to influxdb"),
             };
         }
@@ -234,22 +499,3 @@ async fn handle_connection(
         warn!("connection closed");
     }
 }
-
-async fn get_ac_production(client: &Arc<Mutex<Transport>>) -> i64 {
-    let mut cl = client.lock().await;
-
-    // Read the power value, if it fails reconnect to the modbus client
-    let (power_value, power_scale_factor) = match cl.read_holding_registers(83, 2) {
-        Ok(v) => (*v.first().unwrap() as f64, *v.last().unwrap()),
-        Err(e) => {
-            let _ = std::mem::replace(&mut *cl, get_modbus_pipe());
-            error!(error = ?e, "failed to read power value");
-            panic!("failed to read power value");
-        }
-    };
-
-    let power_scale_factor = power_scale_factor as i16;
-
-    // Calculate the actual AC value
-    (power_value * (10_f64.powf(power_scale_factor as f64))).floor() as i64
-}
@@ -0,0 +1,228 @@
+//! Declarative description of which Modbus holding registers to read for each
+//! metric, so a different SunSpec/Modbus inverter can be supported by editing
+//! a config file instead of recompiling.
+
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use modbus::{Client, Transport};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{get_modbus_pipe, PollError};
+
+/// How a metric's raw register words should be interpreted.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueEncoding {
+    Uint16,
+    Uint32,
+    Int16,
+}
+
+impl ValueEncoding {
+    /// How many consecutive holding registers this encoding decodes from.
+    fn word_count(self) -> u16 {
+        match self {
+            ValueEncoding::Uint16 | ValueEncoding::Int16 => 1,
+            ValueEncoding::Uint32 => 2,
+        }
+    }
+}
+
+/// Marks a metric as one the poll loop depends on by meaning rather than by
+/// name, so operators can rename (or relocate) it in a custom register map
+/// without breaking the production/consumption calculations that consume it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricRole {
+    Production,
+    ProductionLifetime,
+}
+
+/// A single metric to poll from the inverter's register map.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegisterMetric {
+    pub name: String,
+    pub register: u16,
+    pub word_count: u16,
+    pub encoding: ValueEncoding,
+    /// A single holding register holding a signed power-of-ten scale factor
+    /// that the decoded value is multiplied by, if the device reports one.
+    #[serde(default)]
+    pub scale_register: Option<u16>,
+    /// Set on the metrics the poll loop looks up by meaning. Plain metrics
+    /// added declaratively for their own sake leave this unset.
+    #[serde(default)]
+    pub role: Option<MetricRole>,
+}
+
+/// The full set of metrics to poll on each cycle.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegisterMap {
+    pub metrics: Vec<RegisterMetric>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegisterMapError {
+    #[error("failed to read register map file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse register map: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("register map has no metric with role {0:?}")]
+    MissingRole(MetricRole),
+    #[error("metric \"{name}\" has word_count {actual} but its {encoding:?} encoding requires {expected}")]
+    InvalidWordCount {
+        name: String,
+        encoding: ValueEncoding,
+        expected: u16,
+        actual: u16,
+    },
+}
+
+/// Loaded once at startup from `REGISTER_MAP_PATH`, or the built-in SolarEdge
+/// layout if that isn't set.
+pub static REGISTER_MAP: Lazy<RegisterMap> = Lazy::new(RegisterMap::load);
+
+impl RegisterMap {
+    fn load() -> Self {
+        match std::env::var("REGISTER_MAP_PATH") {
+            // An operator explicitly pointed us at a custom map: a broken one must abort
+            // startup rather than silently run against the wrong register layout.
+            Ok(path) => Self::load_from_file(&path)
+                .unwrap_or_else(|e| panic!("invalid register map at {path}: {e}")),
+            Err(_) => Self::default_solaredge(),
+        }
+    }
+
+    fn load_from_file(path: impl AsRef<Path>) -> Result<Self, RegisterMapError> {
+        let contents = fs::read_to_string(path)?;
+        let map: Self = toml::from_str(&contents)?;
+        map.validate()?;
+        info!(metrics = map.metrics.len(), "loaded register map from file");
+        Ok(map)
+    }
+
+    fn default_solaredge() -> Self {
+        let map = Self {
+            metrics: vec![
+                RegisterMetric {
+                    name: "production".to_string(),
+                    register: 83,
+                    word_count: 1,
+                    encoding: ValueEncoding::Uint16,
+                    scale_register: Some(84),
+                    role: Some(MetricRole::Production),
+                },
+                RegisterMetric {
+                    name: "production_lifetime".to_string(),
+                    register: 93,
+                    word_count: 2,
+                    encoding: ValueEncoding::Uint32,
+                    scale_register: Some(95),
+                    role: Some(MetricRole::ProductionLifetime),
+                },
+            ],
+        };
+        map.validate()
+            .expect("the built-in default register map must cover every required role");
+        map
+    }
+
+    /// Fails fast at load time rather than letting a typo'd or incomplete
+    /// custom map surface only as per-packet lookup errors at runtime.
+    fn validate(&self) -> Result<(), RegisterMapError> {
+        for role in [MetricRole::Production, MetricRole::ProductionLifetime] {
+            if self.get_by_role(role).is_none() {
+                return Err(RegisterMapError::MissingRole(role));
+            }
+        }
+
+        for metric in &self.metrics {
+            let expected = metric.encoding.word_count();
+            if metric.word_count != expected {
+                return Err(RegisterMapError::InvalidWordCount {
+                    name: metric.name.clone(),
+                    encoding: metric.encoding,
+                    expected,
+                    actual: metric.word_count,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_by_role(&self, role: MetricRole) -> Option<&RegisterMetric> {
+        self.metrics.iter().find(|m| m.role == Some(role))
+    }
+}
+
+/// Read a single metric's value words (and optional scale register) and
+/// return the scaled value.
+fn read_metric(cl: &mut Transport, metric: &RegisterMetric) -> Result<f64, PollError> {
+    let words = cl.read_holding_registers(metric.register, metric.word_count)?;
+
+    let raw = match (metric.encoding, words.as_slice()) {
+        (ValueEncoding::Uint16, [v]) => *v as f64,
+        (ValueEncoding::Int16, [v]) => *v as i16 as f64,
+        (ValueEncoding::Uint32, [hi, lo]) => (((*hi as u32) << 16) | (*lo as u32)) as f64,
+        _ => {
+            return Err(PollError::MalformedResponse(
+                "register word count did not match the metric's value encoding",
+            ))
+        }
+    };
+
+    let scale = match metric.scale_register {
+        Some(scale_register) => {
+            let scale_words = cl.read_holding_registers(scale_register, 1)?;
+            let scale = *scale_words
+                .first()
+                .ok_or(PollError::MalformedResponse("expected 1 register for scale factor"))?
+                as i16;
+            10_f64.powf(scale as f64)
+        }
+        None => 1.0,
+    };
+
+    Ok((raw * scale).floor())
+}
+
+/// Read every metric in the register map. A metric that fails to read is
+/// logged and omitted rather than aborting the rest of the map; the shared
+/// transport is reconnected whenever the modbus client itself returns an
+/// error, but not for a decoding mismatch caught here (which a reconnect
+/// can't fix anyway).
+pub async fn read_all(client: &Arc<Mutex<Transport>>, map: &RegisterMap) -> HashMap<String, f64> {
+    let mut values = HashMap::with_capacity(map.metrics.len());
+
+    for metric in &map.metrics {
+        let mut cl = client.lock().await;
+        match read_metric(&mut cl, metric) {
+            Ok(value) => {
+                values.insert(metric.name.clone(), value);
+            }
+            Err(PollError::Modbus(e)) => {
+                warn!(error = ?e, metric = %metric.name, "failed to read register metric, reconnecting to modbus client");
+                match get_modbus_pipe() {
+                    Ok(transport) => {
+                        let _ = std::mem::replace(&mut *cl, transport);
+                    }
+                    Err(e) => {
+                        error!(error = ?e, "failed to reconnect to modbus client, will retry next cycle");
+                    }
+                }
+            }
+            // Not a connectivity problem — a local config/encoding mismatch, or an
+            // inverter replying with fewer registers than asked for. Reconnecting the
+            // live transport wouldn't fix it, so just skip this metric for this cycle.
+            Err(e) => {
+                warn!(error = ?e, metric = %metric.name, "failed to read register metric");
+            }
+        }
+    }
+
+    values
+}